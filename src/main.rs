@@ -1,38 +1,146 @@
-use std::cmp::Ordering;
-use std::collections::BTreeSet;
-use std::iter::FromIterator;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use clap::{App, Arg};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rayon::prelude::*;
+use serde::Serialize;
+use twox_hash::xxh3::HasherExt;
 
-#[derive(Clone, Debug)]
-struct File {
-    path: PathBuf,
-    hash: u64,
+/// Number of bytes read from the start of a file for the partial-hash stage.
+const BLOCK_SIZE: u64 = 4096;
+
+/// How results are presented, selected via `--format`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum OutputFormat {
+    /// Human-readable progress messages and results.
+    Text,
+    /// A single structured JSON report, with progress messages suppressed.
+    Json,
 }
 
-impl Ord for File {
-    fn cmp(&self, other: &File) -> Ordering {
-        self.hash.cmp(&other.hash)
+impl OutputFormat {
+    fn parse(value: &str) -> OutputFormat {
+        match value {
+            "json" => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        }
     }
 }
 
-impl PartialOrd for File {
-    fn partial_cmp(&self, other: &File) -> Option<Ordering> {
-        Some(self.cmp(other))
+/// Prints `message` if `format` calls for human-readable text output.
+fn log(format: OutputFormat, message: &str) {
+    if format == OutputFormat::Text {
+        println!("{}", message);
     }
 }
 
-impl Eq for File {}
+/// The hashing algorithm used to compare file contents, selected via `--hash`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum HashType {
+    /// Fast, non-cryptographic hash. The default: good enough to tell files
+    /// apart for reporting purposes, but not for deciding what to delete.
+    Xxh3,
+    /// Cryptographic hash, for when matches are used to decide what to delete.
+    Blake3,
+    /// Very fast, weak checksum, useful as a quick first pass.
+    Crc32,
+}
 
-impl PartialEq for File {
-    fn eq(&self, other: &File) -> bool {
-        self.hash == other.hash
+impl HashType {
+    fn parse(value: &str) -> HashType {
+        match value {
+            "blake3" => HashType::Blake3,
+            "crc32" => HashType::Crc32,
+            _ => HashType::Xxh3,
+        }
     }
 }
 
-use std::hash::Hasher;
-use std::io;
+/// Restricts which files and directories a traversal visits, built from the
+/// `--include-ext`, `--exclude-ext` and `--exclude-dir` arguments.
+struct Filters {
+    include_exts: Option<HashSet<String>>,
+    exclude_exts: HashSet<String>,
+    exclude_dirs: GlobSet,
+}
+
+impl Filters {
+    fn new(include_ext: Option<&str>, exclude_ext: Option<&str>, exclude_dir: Option<&str>) -> Filters {
+        Filters {
+            include_exts: include_ext.map(parse_ext_list),
+            exclude_exts: exclude_ext.map(parse_ext_list).unwrap_or_default(),
+            exclude_dirs: parse_dir_globs(exclude_dir),
+        }
+    }
+
+    /// Whether a file with the given path should be included in a traversal.
+    fn accepts_file(&self, path: &Path) -> bool {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+
+        if self.exclude_exts.contains(&ext) {
+            return false;
+        }
+        match &self.include_exts {
+            Some(include_exts) => include_exts.contains(&ext),
+            None => true,
+        }
+    }
+
+    /// Whether a directory with the given path should be excluded, and its
+    /// contents not descended into.
+    fn excludes_dir(&self, path: &Path) -> bool {
+        path.file_name()
+            .map(|name| self.exclude_dirs.is_match(name))
+            .unwrap_or(false)
+    }
+}
+
+fn parse_ext_list(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(|ext| ext.trim().to_lowercase())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+fn parse_dir_globs(list: Option<&str>) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in list.unwrap_or("").split(',') {
+        let pattern = pattern.trim();
+        if !pattern.is_empty() {
+            builder.add(Glob::new(pattern).expect("invalid --exclude-dir pattern"));
+        }
+    }
+    builder.build().expect("invalid --exclude-dir patterns")
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct File {
+    path: PathBuf,
+    len: u64,
+    partial_hash: Option<u128>,
+    full_hash: Option<u128>,
+}
+
+impl File {
+    fn new(path: PathBuf, len: u64) -> File {
+        File {
+            path,
+            len,
+            partial_hash: None,
+            full_hash: None,
+        }
+    }
+}
 
 struct HashWriter<T: Hasher>(T);
 
@@ -51,47 +159,164 @@ impl<T: Hasher> io::Write for HashWriter<T> {
     }
 }
 
-fn hash_file(path: &Path) -> u64 {
-    if let Ok(file) = std::fs::File::open(path) {
-        let mut reader = io::BufReader::new(file);
-        let mut hash_writer = HashWriter(twox_hash::XxHash::with_seed(0));
+/// Adapts `crc32fast::Hasher`, which has its own update/finalize API, to
+/// `std::hash::Hasher` so it can be used through `HashWriter` like the other
+/// non-cryptographic backends.
+#[derive(Clone)]
+struct Crc32Hasher(crc32fast::Hasher);
+
+impl Hasher for Crc32Hasher {
+    fn finish(&self) -> u64 {
+        u64::from(self.clone().0.finalize())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+/// Hashes the bytes read from `reader` using `hash_type`.
+fn hash_reader<R: io::Read>(mut reader: R, hash_type: HashType) -> u128 {
+    match hash_type {
+        HashType::Xxh3 => {
+            let mut hash_writer = HashWriter(twox_hash::xxh3::Hash128::with_seed(0));
+            io::copy(&mut reader, &mut hash_writer).unwrap();
+            hash_writer.0.finish_ext()
+        }
+        HashType::Crc32 => {
+            let mut hash_writer = HashWriter(Crc32Hasher(crc32fast::Hasher::new()));
+            io::copy(&mut reader, &mut hash_writer).unwrap();
+            u128::from(hash_writer.0.finish())
+        }
+        HashType::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            io::copy(&mut reader, &mut hasher).unwrap();
+            u128::from_le_bytes(hasher.finalize().as_bytes()[..16].try_into().unwrap())
+        }
+    }
+}
 
-        io::copy(&mut reader, &mut hash_writer).unwrap();
-        hash_writer.0.finish()
+fn hash_file(path: &Path, hash_type: HashType) -> u128 {
+    if let Ok(file) = std::fs::File::open(path) {
+        hash_reader(io::BufReader::new(file), hash_type)
     } else {
         eprintln!("Could not open {}", path.display());
         0
     }
 }
 
-fn file_collection<T: FromIterator<File>>(directory: &Path) -> T {
-    println!(
-        "Calculating hashes of files in {} recursively...",
-        directory.display()
-    );
+/// Hashes only the first `BLOCK_SIZE` bytes of the file at `path`, so that
+/// files which turn out not to share any content can be told apart without
+/// reading them in full.
+fn hash_file_partial(path: &Path, hash_type: HashType) -> u128 {
+    if let Ok(file) = std::fs::File::open(path) {
+        hash_reader(io::BufReader::new(file).take(BLOCK_SIZE), hash_type)
+    } else {
+        eprintln!("Could not open {}", path.display());
+        0
+    }
+}
 
+/// Walks `directory` recursively and records the path and length of every
+/// file found, without reading any file content. Directories matched by
+/// `filters` are not descended into, and files they exclude are skipped.
+fn walk_files(directory: &Path, filters: &Filters) -> Vec<File> {
     walkdir::WalkDir::new(directory)
         .into_iter()
+        .filter_entry(|e| !e.file_type().is_dir() || !filters.excludes_dir(e.path()))
         .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_file())
-        .map(|entry| File {
-            path: entry.path().to_path_buf(),
-            hash: hash_file(entry.path()),
+        .filter(|e| e.path().is_file() && filters.accepts_file(e.path()))
+        .filter_map(|entry| {
+            entry
+                .metadata()
+                .ok()
+                .map(|metadata| File::new(entry.path().to_path_buf(), metadata.len()))
         })
         .collect()
 }
 
-fn find_empty_dirs(directory: &Path) -> Vec<PathBuf> {
+/// Groups `files` by the given key, which is cheap to compute and does not
+/// require reading file content (e.g. length or a previously computed hash).
+fn group_by<K: Ord, F: Fn(&File) -> K>(files: Vec<File>, key: F) -> BTreeMap<K, Vec<File>> {
+    let mut groups: BTreeMap<K, Vec<File>> = BTreeMap::new();
+    for file in files {
+        groups.entry(key(&file)).or_default().push(file);
+    }
+    groups
+}
+
+/// Flattens `groups`, discarding any group with a single member: a file that
+/// doesn't share its key with any other file cannot have a duplicate.
+fn collisions<K: Ord>(groups: BTreeMap<K, Vec<File>>) -> Vec<File> {
+    groups
+        .into_values()
+        .filter(|files| files.len() > 1)
+        .flatten()
+        .collect()
+}
+
+/// Compares the contents of the files at `path1` and `path2` byte for byte.
+fn files_equal(path1: &Path, path2: &Path) -> io::Result<bool> {
+    let mut reader1 = io::BufReader::new(std::fs::File::open(path1)?);
+    let mut reader2 = io::BufReader::new(std::fs::File::open(path2)?);
+    let mut buf1 = [0u8; BLOCK_SIZE as usize];
+    let mut buf2 = [0u8; BLOCK_SIZE as usize];
+
+    loop {
+        let bytes_read1 = reader1.read(&mut buf1)?;
+        let bytes_read2 = reader2.read(&mut buf2)?;
+
+        if bytes_read1 != bytes_read2 || buf1[..bytes_read1] != buf2[..bytes_read2] {
+            return Ok(false);
+        }
+        if bytes_read1 == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Splits `paths`, which are known to share a hash, into groups that are
+/// actually byte-for-byte identical. This guards against false positives
+/// from hash collisions before anything gets deleted.
+fn verified_duplicate_groups(paths: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+
+    'paths: for path in paths {
+        for group in &mut groups {
+            if files_equal(&group[0], &path).unwrap_or(false) {
+                group.push(path);
+                continue 'paths;
+            }
+        }
+        groups.push(vec![path]);
+    }
+
+    groups
+}
+
+/// Finds directories that are empty, or that would become empty once every
+/// path in `removed` has been deleted (used to preview `--dry-run` results
+/// against the tree as it stands before any deletions).
+fn find_empty_dirs(directory: &Path, filters: &Filters, removed: &HashSet<PathBuf>) -> Vec<PathBuf> {
     walkdir::WalkDir::new(directory)
         .into_iter()
+        .filter_entry(|e| !e.file_type().is_dir() || !filters.excludes_dir(e.path()))
         .filter_map(|e| e.ok())
-        .filter(|e| is_empty_dir(e.path()))
+        .filter(|e| is_empty_dir(e.path(), removed))
         .map(|e| e.path().to_path_buf())
         .collect()
 }
 
-fn is_empty_dir(path: &Path) -> bool {
-    path.is_dir() && std::fs::read_dir(path).map(|i| i.count()).unwrap_or(0) == 0
+fn is_empty_dir(path: &Path, removed: &HashSet<PathBuf>) -> bool {
+    path.is_dir()
+        && std::fs::read_dir(path)
+            .map(|entries| {
+                entries.all(|entry| match entry {
+                    Ok(entry) => removed.contains(&entry.path()),
+                    Err(_) => false,
+                })
+            })
+            .unwrap_or(true)
 }
 
 fn parent_dir_is_date(path: &Path) -> bool {
@@ -103,76 +328,352 @@ fn parent_dir_is_date(path: &Path) -> bool {
         .starts_with("20")
 }
 
-fn delete_duplicates(paths: &[PathBuf]) {
+/// Deletes the duplicates in `paths` that live in a date-named directory,
+/// unless every one of them does (see `parent_dir_is_date`). Returns the
+/// paths that were deleted, or that would have been deleted had `dry_run`
+/// not been set.
+fn delete_duplicates(paths: &[PathBuf], dry_run: bool, format: OutputFormat) -> Vec<PathBuf> {
     let in_album = paths.into_iter().any(|path| !parent_dir_is_date(path));
+    let mut deleted = Vec::new();
 
     if in_album && paths.len() > 1 {
-        paths.into_iter().for_each(|path| {
+        for path in paths {
             if parent_dir_is_date(path) {
-                println!("Deleting {}", path.display());
-                std::fs::remove_file(path).unwrap();
+                if dry_run {
+                    log(format, &format!("Would delete {}", path.display()));
+                } else {
+                    log(format, &format!("Deleting {}", path.display()));
+                    std::fs::remove_file(path).unwrap();
+                }
+                deleted.push(path.clone());
             }
-        })
+        }
+    }
+
+    deleted
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Origin {
+    Dir1,
+    Dir2,
+}
+
+/// Splits `entries` into groups sharing the given key, moving any group that
+/// isn't represented in both directories straight into `unique` (such files
+/// cannot have a match on the other side, so there's no need to hash them
+/// any further), and returning the rest for the next stage.
+fn partition_by_origin<K: Ord, F: Fn(&File) -> K>(
+    entries: Vec<(Origin, File)>,
+    key: F,
+    unique: &mut Vec<(Origin, File)>,
+) -> Vec<(Origin, File)> {
+    let mut groups: BTreeMap<K, Vec<(Origin, File)>> = BTreeMap::new();
+    for entry in entries {
+        groups.entry(key(&entry.1)).or_default().push(entry);
+    }
+
+    let mut remaining = Vec::new();
+    for group in groups.into_values() {
+        let in_both = group.iter().any(|(o, _)| *o == Origin::Dir1)
+            && group.iter().any(|(o, _)| *o == Origin::Dir2);
+
+        if in_both {
+            remaining.extend(group);
+        } else {
+            unique.extend(group);
+        }
+    }
+    remaining
+}
+
+/// Serializes a hash as a fixed-width hex string rather than a bare number,
+/// since values above 2^53 lose precision in JSON parsers backed by a
+/// double (e.g. JavaScript's).
+fn serialize_hash_as_hex<S: serde::Serializer>(hash: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:032x}", hash))
+}
+
+/// A file reported as unique to one directory. Unlike the internal `File`
+/// pipeline type, `hash` is always populated, so downstream consumers see a
+/// stable schema regardless of which stage resolved the file as unique.
+#[derive(Serialize)]
+struct UniqueFile {
+    path: PathBuf,
+    size: u64,
+    #[serde(serialize_with = "serialize_hash_as_hex")]
+    hash: u128,
+}
+
+impl UniqueFile {
+    /// Builds a report entry for `file`. If no stage needed to hash it in
+    /// full (the common case when the two directories are largely
+    /// disjoint), this falls back to the cheaper partial hash rather than
+    /// re-reading the whole file just for the report.
+    fn from_file(file: File, hash_type: HashType) -> UniqueFile {
+        let hash = file
+            .full_hash
+            .or(file.partial_hash)
+            .unwrap_or_else(|| hash_file_partial(&file.path, hash_type));
+
+        UniqueFile {
+            path: file.path,
+            size: file.len,
+            hash,
+        }
     }
 }
 
-fn diff_directories(dir1: &Path, dir2: &Path) -> Vec<PathBuf> {
-    let now = std::time::SystemTime::now();
-    let dir1_files: BTreeSet<File> = file_collection(&dir1);
-    println!(
-        "Took {:?} to hash {} files.",
-        now.elapsed().unwrap(),
-        dir1_files.len()
+/// The files found in each directory that have no match in the other, as
+/// returned by `diff_directories`. Kept as the internal `File` pipeline
+/// type so that building a `DiffReport` (and the hashing that can entail)
+/// is only paid for when one is actually needed.
+struct DiffResult {
+    unique_to_dir1: Vec<File>,
+    unique_to_dir2: Vec<File>,
+}
+
+/// A machine-readable `--format json` report of a `DiffResult`.
+#[derive(Serialize)]
+struct DiffReport {
+    unique_to_dir1: Vec<UniqueFile>,
+    unique_to_dir2: Vec<UniqueFile>,
+}
+
+impl DiffReport {
+    fn from_result(result: DiffResult, hash_type: HashType) -> DiffReport {
+        DiffReport {
+            unique_to_dir1: result
+                .unique_to_dir1
+                .into_iter()
+                .map(|file| UniqueFile::from_file(file, hash_type))
+                .collect(),
+            unique_to_dir2: result
+                .unique_to_dir2
+                .into_iter()
+                .map(|file| UniqueFile::from_file(file, hash_type))
+                .collect(),
+        }
+    }
+}
+
+fn diff_directories(
+    dir1: &Path,
+    dir2: &Path,
+    hash_type: HashType,
+    filters: &Filters,
+    format: OutputFormat,
+) -> DiffResult {
+    log(
+        format,
+        &format!("Diffing the directories {} and {}", dir1.display(), dir2.display()),
     );
+    let now = SystemTime::now();
 
-    let now = std::time::SystemTime::now();
-    let dir2_files: BTreeSet<File> = file_collection(dir2);
-    println!(
-        "Took {:?} to hash {} files.",
-        now.elapsed().unwrap(),
-        dir2_files.len()
+    let entries: Vec<(Origin, File)> = walk_files(dir1, filters)
+        .into_iter()
+        .map(|file| (Origin::Dir1, file))
+        .chain(
+            walk_files(dir2, filters)
+                .into_iter()
+                .map(|file| (Origin::Dir2, file)),
+        )
+        .collect();
+    log(format, &format!("Found {} files in total.", entries.len()));
+
+    let mut unique: Vec<(Origin, File)> = Vec::new();
+
+    let candidates = partition_by_origin(entries, |file| file.len, &mut unique);
+    log(
+        format,
+        &format!(
+            "{} files remain after comparing sizes, hashing the first {} bytes of each...",
+            candidates.len(),
+            BLOCK_SIZE
+        ),
     );
 
-    println!("Determining symmetric difference between file sets...");
-    dir1_files
-        .symmetric_difference(&dir2_files)
-        .map(|f| f.path.clone())
-        .collect()
+    let mut candidates = candidates;
+    candidates.par_iter_mut().for_each(|(_, file)| {
+        file.partial_hash = Some(hash_file_partial(&file.path, hash_type));
+    });
+
+    let candidates = partition_by_origin(candidates, |file| file.partial_hash, &mut unique);
+    log(
+        format,
+        &format!(
+            "{} files remain after comparing partial hashes, hashing each in full...",
+            candidates.len()
+        ),
+    );
+
+    let mut candidates = candidates;
+    candidates.par_iter_mut().for_each(|(_, file)| {
+        file.full_hash = Some(hash_file(&file.path, hash_type));
+    });
+
+    let mut by_full_hash: BTreeMap<Option<u128>, Vec<(Origin, File)>> = BTreeMap::new();
+    for entry in candidates {
+        by_full_hash.entry(entry.1.full_hash).or_default().push(entry);
+    }
+    for group in by_full_hash.into_values() {
+        let in_both = group.iter().any(|(o, _)| *o == Origin::Dir1)
+            && group.iter().any(|(o, _)| *o == Origin::Dir2);
+
+        if !in_both {
+            unique.extend(group);
+        }
+    }
+
+    log(
+        format,
+        &format!("Took {:?} to diff the directories.", now.elapsed().unwrap()),
+    );
+
+    let mut unique_to_dir1 = Vec::new();
+    let mut unique_to_dir2 = Vec::new();
+    for (origin, file) in unique {
+        match origin {
+            Origin::Dir1 => unique_to_dir1.push(file),
+            Origin::Dir2 => unique_to_dir2.push(file),
+        }
+    }
+
+    DiffResult {
+        unique_to_dir1,
+        unique_to_dir2,
+    }
 }
 
-fn find_and_delete_duplicates(directory: &Path) {
-    let now = std::time::SystemTime::now();
-    let mut files: Vec<File> = file_collection(&directory);
-    println!(
-        "Took {:?} to hash {} files.",
-        now.elapsed().unwrap(),
-        files.len()
+/// A group of files that share a hash, as reported for `--format json`.
+#[derive(Serialize)]
+struct DuplicateGroup {
+    #[serde(serialize_with = "serialize_hash_as_hex")]
+    hash: u128,
+    paths: Vec<PathBuf>,
+}
+
+/// The result of `find_and_delete_duplicates`.
+#[derive(Serialize)]
+struct DuplicatesReport {
+    duplicate_groups: Vec<DuplicateGroup>,
+    empty_dirs: Vec<PathBuf>,
+    deleted: Vec<PathBuf>,
+    dry_run: bool,
+}
+
+fn find_and_delete_duplicates(
+    directory: &Path,
+    hash_type: HashType,
+    verify: bool,
+    filters: &Filters,
+    format: OutputFormat,
+    dry_run: bool,
+) {
+    log(
+        format,
+        &format!(
+            "Removing duplicate files and empty directories in {}",
+            directory.display()
+        ),
     );
+    let now = SystemTime::now();
 
-    files.sort_unstable();
+    let files = walk_files(directory, filters);
+    log(format, &format!("Found {} files in total.", files.len()));
 
-    let mut last_hash: Option<u64> = None;
-    let mut current_run: Vec<PathBuf> = Vec::new();
-    for file in files {
-        match last_hash {
-            Some(h) if h != file.hash => {
-                delete_duplicates(&current_run);
-                current_run.clear();
-                last_hash = Some(file.hash);
+    let candidates = collisions(group_by(files, |file| file.len));
+    log(
+        format,
+        &format!(
+            "{} files remain after comparing sizes, hashing the first {} bytes of each...",
+            candidates.len(),
+            BLOCK_SIZE
+        ),
+    );
+
+    let mut candidates = candidates;
+    candidates.par_iter_mut().for_each(|file| {
+        file.partial_hash = Some(hash_file_partial(&file.path, hash_type));
+    });
+
+    let candidates = collisions(group_by(candidates, |file| file.partial_hash));
+    log(
+        format,
+        &format!(
+            "{} files remain after comparing partial hashes, hashing each in full...",
+            candidates.len()
+        ),
+    );
+
+    let mut candidates = candidates;
+    candidates.par_iter_mut().for_each(|file| {
+        file.full_hash = Some(hash_file(&file.path, hash_type));
+    });
+    log(
+        format,
+        &format!("Took {:?} to hash candidate files.", now.elapsed().unwrap()),
+    );
+
+    let mut duplicate_groups = Vec::new();
+    let mut deleted = Vec::new();
+
+    for group in group_by(candidates, |file| file.full_hash).into_values() {
+        if group.len() <= 1 {
+            continue;
+        }
+        let hash = group[0].full_hash.unwrap_or_default();
+        let paths: Vec<PathBuf> = group.into_iter().map(|file| file.path).collect();
+
+        let verified_groups = if verify {
+            verified_duplicate_groups(paths)
+        } else {
+            vec![paths]
+        };
+
+        for verified in verified_groups {
+            if verified.len() > 1 {
+                deleted.extend(delete_duplicates(&verified, dry_run, format));
+                duplicate_groups.push(DuplicateGroup {
+                    hash,
+                    paths: verified,
+                });
             }
-            None => last_hash = Some(file.hash),
-            _ => {}
         }
-        current_run.push(file.path.clone());
     }
 
-    let now = std::time::SystemTime::now();
-    let files = find_empty_dirs(&directory);
-    println!("Took {:?} to find empty dirs.", now.elapsed().unwrap());
+    let removed: HashSet<PathBuf> = if dry_run {
+        deleted.iter().cloned().collect()
+    } else {
+        HashSet::new()
+    };
 
-    files
-        .into_iter()
-        .for_each(|path| std::fs::remove_dir(path).unwrap());
+    let now = SystemTime::now();
+    let empty_dirs = find_empty_dirs(directory, filters, &removed);
+    log(
+        format,
+        &format!("Took {:?} to find empty dirs.", now.elapsed().unwrap()),
+    );
+
+    if dry_run {
+        for path in &empty_dirs {
+            log(format, &format!("Would remove empty directory {}", path.display()));
+        }
+    } else {
+        empty_dirs
+            .iter()
+            .for_each(|path| std::fs::remove_dir(path).unwrap());
+    }
+
+    if format == OutputFormat::Json {
+        let report = DuplicatesReport {
+            duplicate_groups,
+            empty_dirs,
+            deleted,
+            dry_run,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    }
 }
 
 fn main() {
@@ -194,24 +695,159 @@ fn main() {
                 )
                 .index(2),
         )
+        .arg(
+            Arg::with_name("hash")
+                .long("hash")
+                .takes_value(true)
+                .possible_values(&["xxh3", "blake3", "crc32"])
+                .default_value("xxh3")
+                .help(
+                    "The hashing algorithm used to compare file contents. \
+                     Use blake3 for a cryptographically strong digest when \
+                     matches are used to decide what to delete, or crc32 \
+                     for a faster, weaker first pass.",
+                ),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .help("The number of threads to hash files with. Defaults to the number of CPU cores."),
+        )
+        .arg(
+            Arg::with_name("no-verify")
+                .long("no-verify")
+                .help(
+                    "Skip the final byte-for-byte comparison of files that \
+                     hash as equal before deleting duplicates. Only used \
+                     when no second directory is given.",
+                ),
+        )
+        .arg(
+            Arg::with_name("include-ext")
+                .long("include-ext")
+                .takes_value(true)
+                .help("Only scan files with one of these comma-separated extensions, e.g. jpg,png,mp4."),
+        )
+        .arg(
+            Arg::with_name("exclude-ext")
+                .long("exclude-ext")
+                .takes_value(true)
+                .help("Don't scan files with one of these comma-separated extensions."),
+        )
+        .arg(
+            Arg::with_name("exclude-dir")
+                .long("exclude-dir")
+                .takes_value(true)
+                .help(
+                    "Don't descend into directories matching one of these \
+                     comma-separated glob patterns, e.g. node_modules,.git.",
+                ),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("The format results are reported in."),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help(
+                    "Report what would be deleted or removed, without \
+                     deleting or removing anything. Only used when no \
+                     second directory is given.",
+                ),
+        )
         .get_matches();
 
     let dir1 = matches.value_of("dir1").map(Path::new).unwrap();
-    let dir2_option = matches.value_of("dir1").map(Path::new);
+    let dir2_option = matches.value_of("dir2").map(Path::new);
+    let hash_type = HashType::parse(matches.value_of("hash").unwrap());
+    let verify = !matches.is_present("no-verify");
+    let format = OutputFormat::parse(matches.value_of("format").unwrap());
+    let dry_run = matches.is_present("dry-run");
+    let filters = Filters::new(
+        matches.value_of("include-ext"),
+        matches.value_of("exclude-ext"),
+        matches.value_of("exclude-dir"),
+    );
+
+    if let Some(threads) = matches.value_of("threads") {
+        let threads: usize = threads.parse().expect("--threads must be a number");
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
 
     if let Some(dir2) = dir2_option {
-        println!(
-            "Diffing the directories {} and {}",
-            dir1.display(),
-            dir2.display()
-        );
-        let unmatched_files = diff_directories(dir1, dir2);
-        println!("{:?}", unmatched_files);
+        let result = diff_directories(dir1, dir2, hash_type, &filters, format);
+        match format {
+            OutputFormat::Json => {
+                let report = DiffReport::from_result(result, hash_type);
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            }
+            OutputFormat::Text => {
+                let unmatched_files: Vec<&PathBuf> = result
+                    .unique_to_dir1
+                    .iter()
+                    .chain(result.unique_to_dir2.iter())
+                    .map(|file| &file.path)
+                    .collect();
+                println!("{:?}", unmatched_files);
+            }
+        }
     } else {
-        println!(
-            "Removing duplicate files and empty directories in {}",
-            dir1.display()
-        );
-        find_and_delete_duplicates(dir1);
+        find_and_delete_duplicates(dir1, hash_type, verify, &filters, format, dry_run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Creates a file at `path` (creating its parent directory if needed)
+    /// with the given contents.
+    fn write_file(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    /// Simulates a hash collision (two files that a prior pipeline stage
+    /// believed were duplicates because they share a hash, but which don't
+    /// actually share content) and checks that the verify guard only lets
+    /// the genuinely identical files through to deletion.
+    #[test]
+    fn verify_guard_prevents_deleting_a_hash_collision() {
+        let dir = std::env::temp_dir().join(format!("dir-diff-test-{}", std::process::id()));
+        let keeper = dir.join("album").join("keeper.bin");
+        let duplicate = dir.join("2020-01-01").join("duplicate.bin");
+        let collision = dir.join("2020-01-01").join("collision.bin");
+
+        write_file(&keeper, b"identical content");
+        write_file(&duplicate, b"identical content");
+        write_file(&collision, b"different content, same hash bucket");
+
+        // All three are presumed to share a hash, as if `full_hash` had
+        // collided across genuinely different files.
+        let presumed_duplicates = vec![keeper.clone(), duplicate.clone(), collision.clone()];
+
+        let mut deleted = Vec::new();
+        for verified in verified_duplicate_groups(presumed_duplicates) {
+            if verified.len() > 1 {
+                deleted.extend(delete_duplicates(&verified, false, OutputFormat::Text));
+            }
+        }
+
+        assert_eq!(deleted, vec![duplicate.clone()]);
+        assert!(!duplicate.exists(), "the true duplicate should have been deleted");
+        assert!(collision.exists(), "the hash collision must survive deletion");
+        assert!(keeper.exists(), "the album copy should never be deleted");
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }